@@ -4,11 +4,13 @@
 use clap::Parser;
 use jwalk::WalkDir;
 use std::path::{Path, PathBuf};
-use std::os::unix::fs::MetadataExt;
-use std::io::{self, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::io::{self, Read, Write};
 use std::time::Instant;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
+use std::sync::mpsc::Sender;
 
 // 引入 Btrfs 检查所需的库
 use nix::sys::statfs;
@@ -37,6 +39,236 @@ struct Args {
     /// 排除隐藏文件和目录（以 '.' 开头）进行搜索。默认包含隐藏文件。
     #[clap(short = 'i', long)]
     skip_hidden: bool,
+
+    /// Reflink 匹配所需的最小共享块百分比（0-100）。默认 100 仅匹配完全共享的副本；
+    /// 设为更低的值可识别在 CoW 文件系统上被部分重写、但仍共享大部分物理块的文件。
+    #[clap(short = 'm', long, default_value_t = 100.0)]
+    min_shared: f64,
+
+    /// 去重模式：对内容与目标逐字节一致、但尚未共享物理块的文件，
+    /// 使用内核 FIDEDUPERANGE 将其去重到目标，从而回收空间。
+    #[clap(long)]
+    dedupe: bool,
+
+    /// Clone 模式：对独立副本使用 FICLONE 重建为指向目标的全新 reflink（会覆盖副本内容）。
+    #[clap(long)]
+    clone: bool,
+
+    /// 配合 --dedupe/--clone：只报告将要执行的操作和可回收的字节数，不修改任何文件。
+    #[clap(long)]
+    dry_run: bool,
+
+    /// 仅保留属主 UID 为 <n> 的候选。
+    #[clap(long)]
+    uid: Option<u32>,
+
+    /// 仅保留属组 GID 为 <n> 的候选。
+    #[clap(long)]
+    gid: Option<u32>,
+
+    /// 仅保留权限位匹配 <octal>（如 644、4755）的候选。
+    #[clap(long, value_parser = parse_octal_mode)]
+    mode: Option<u32>,
+
+    /// 输出格式：human（默认人类可读摘要）、json（完整 JSON 文档）、
+    /// ndjson（每行一条记录，随匹配流式输出）。
+    #[clap(long, value_enum, default_value_t = OutputFormat::Human)]
+    format: OutputFormat,
+}
+
+/// 结果输出格式。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    Human,
+    Json,
+    Ndjson,
+}
+
+/// 将八进制字符串（如 "644"、"4755"）解析为权限位。
+fn parse_octal_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s.trim_start_matches("0o"), 8)
+        .map_err(|e| format!("无效的八进制权限位 '{}': {}", s, e))
+}
+
+/// 对候选文件按 POSIX 属主/权限位进行过滤的条件（在 process_read_dir 剪枝阶段应用）。
+#[derive(Clone, Copy, Default)]
+struct MetaFilter {
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mode: Option<u32>,
+}
+
+impl MetaFilter {
+    /// 从命令行参数构造过滤条件。
+    fn from_args(args: &Args) -> MetaFilter {
+        MetaFilter { uid: args.uid, gid: args.gid, mode: args.mode }
+    }
+
+    /// 是否设置了任一过滤条件。
+    fn is_active(&self) -> bool {
+        self.uid.is_some() || self.gid.is_some() || self.mode.is_some()
+    }
+
+    /// 判断条目元数据是否满足所有已指定的过滤条件（权限位只比较低 12 位）。
+    fn matches(&self, metadata: &std::fs::Metadata) -> bool {
+        if let Some(uid) = self.uid {
+            if metadata.uid() != uid {
+                return false;
+            }
+        }
+        if let Some(gid) = self.gid {
+            if metadata.gid() != gid {
+                return false;
+            }
+        }
+        if let Some(mode) = self.mode {
+            if metadata.mode() & 0o7777 != mode {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// ============== 实用函数：文件类型分类 ==============
+
+/// 完整的 POSIX 文件类型分类。用于在结果中标注每个条目的类型，
+/// 并据此决定是否对其做 fiemap 提取（仅常规文件可靠支持）。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileKind {
+    Regular,
+    Dir,
+    Symlink,
+    BlockDevice,
+    CharDevice,
+    Fifo,
+    Socket,
+    Unknown,
+}
+
+impl FileKind {
+    /// 从（lstat 获取的）元数据推断文件类型。
+    fn from_metadata(metadata: &std::fs::Metadata) -> FileKind {
+        let ft = metadata.file_type();
+        if ft.is_file() {
+            FileKind::Regular
+        } else if ft.is_dir() {
+            FileKind::Dir
+        } else if ft.is_symlink() {
+            FileKind::Symlink
+        } else if ft.is_block_device() {
+            FileKind::BlockDevice
+        } else if ft.is_char_device() {
+            FileKind::CharDevice
+        } else if ft.is_fifo() {
+            FileKind::Fifo
+        } else if ft.is_socket() {
+            FileKind::Socket
+        } else {
+            FileKind::Unknown
+        }
+    }
+
+    /// 详细列表中类型列的显示名称。
+    fn label(&self) -> &'static str {
+        match self {
+            FileKind::Regular => "regular",
+            FileKind::Dir => "dir",
+            FileKind::Symlink => "symlink",
+            FileKind::BlockDevice => "block",
+            FileKind::CharDevice => "char",
+            FileKind::Fifo => "fifo",
+            FileKind::Socket => "socket",
+            FileKind::Unknown => "unknown",
+        }
+    }
+}
+
+// ============== 机器可读输出：记录与 JSON 序列化 ==============
+
+/// 匹配的种类，对应详细列表里的状态列。
+enum MatchKind {
+    Hardlink,
+    Symlink,
+    /// Reflink 匹配，附带共享块百分比。
+    Reflink { shared_percent: f64 },
+}
+
+impl MatchKind {
+    fn label(&self) -> &'static str {
+        match self {
+            MatchKind::Hardlink => "hardlink",
+            MatchKind::Symlink => "symlink",
+            MatchKind::Reflink { .. } => "reflink",
+        }
+    }
+}
+
+/// 一条可机器读取的匹配记录：路径、inode、设备号、大小、匹配种类与文件类型。
+struct Record {
+    path: PathBuf,
+    inode: u64,
+    dev: u64,
+    size: u64,
+    kind: FileKind,
+    match_kind: MatchKind,
+}
+
+/// 转义字符串使其可安全嵌入 JSON。
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+impl Record {
+    /// 将记录序列化为单行 JSON 对象。
+    fn to_json(&self) -> String {
+        let shared = match self.match_kind {
+            MatchKind::Reflink { shared_percent } => format!(",\"shared_percent\":{:.2}", shared_percent),
+            _ => String::new(),
+        };
+        format!(
+            "{{\"path\":\"{}\",\"inode\":{},\"dev\":{},\"size\":{},\"type\":\"{}\",\"match\":\"{}\"{}}}",
+            json_escape(&self.path.to_string_lossy()),
+            self.inode,
+            self.dev,
+            self.size,
+            self.kind.label(),
+            self.match_kind.label(),
+            shared,
+        )
+    }
+}
+
+/// 若 ndjson 通道存在，则根据当前条目构造并发送一条记录。
+fn stream_record(
+    tx: &Option<Sender<Record>>,
+    path: &Path,
+    metadata: &std::fs::Metadata,
+    kind: FileKind,
+    match_kind: MatchKind,
+) {
+    if let Some(tx) = tx {
+        let _ = tx.send(Record {
+            path: path.to_path_buf(),
+            inode: metadata.ino(),
+            dev: metadata.dev(),
+            size: metadata.len(),
+            kind,
+            match_kind,
+        });
+    }
 }
 
 // ============== 实用函数：Btrfs 检查 ==============
@@ -51,27 +283,62 @@ fn is_on_btrfs(path: &Path) -> io::Result<bool> {
 
 // ============== 核心逻辑函数：硬链接查找 (高性能) ==============
 
-/// 获取目标文件的 Inode 号、Device ID、链接数 nlink 和文件大小 size
-fn get_target_inode_info(path: &Path) -> io::Result<(u64, u64, u64, u64)> {
+/// 目标文件的核心 Inode 信息：Inode 号、Device ID、链接数 nlink、文件大小 size，
+/// 以及用于审计的 POSIX 权限位 mode、属主 uid/gid 和修改时间 mtime（epoch 秒）。
+struct TargetInfo {
+    inode: u64,
+    dev: u64,
+    nlink: u64,
+    size: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: i64,
+}
+
+/// 获取目标文件的 Inode、设备、链接数、大小及权限/属主/mtime 信息。
+fn get_target_inode_info(path: &Path) -> io::Result<TargetInfo> {
     let metadata = path.metadata()?;
-    
-    Ok((metadata.ino(), metadata.dev(), metadata.nlink(), metadata.len())) 
+
+    Ok(TargetInfo {
+        inode: metadata.ino(),
+        dev: metadata.dev(),
+        nlink: metadata.nlink(),
+        size: metadata.len(),
+        mode: metadata.mode() & 0o7777,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        mtime: metadata.mtime(),
+    })
 }
 
-/// 遍历搜索路径，查找匹配 Inode 号的文件（JWalk 并行，设备 ID 剪枝）
-fn find_hard_links(search_path: &Path, target_inode: u64, target_dev: u64, skip_hidden: bool) -> io::Result<HashSet<PathBuf>> {
-    
+/// 遍历搜索路径，查找匹配 Inode 号的文件（JWalk 并行，设备 ID 剪枝）。
+///
+/// 每个结果都带上其文件类型；规范化后指向目标文件的符号链接也会被作为
+/// `Symlink` 结果报告（它们的 Inode 与目标不同，原先会被漏掉）。
+fn find_hard_links(search_path: &Path, target: &Path, target_inode: u64, target_dev: u64, filter: MetaFilter, tx: Option<Sender<Record>>, skip_hidden: bool) -> io::Result<HashMap<PathBuf, FileKind>> {
+
+    // 预先规范化目标路径，用于判断符号链接是否指向它。
+    let target_canonical = std::fs::canonicalize(target).ok();
+
     let walker = WalkDir::new(search_path)
         .sort(false)
         .skip_hidden(skip_hidden)
         .follow_links(false)
         .max_depth(std::usize::MAX)
         .process_read_dir(move |_depth, _path, _read_dir_state, children| {
-            // 文件系统剪枝：只保留 Device ID 匹配的条目
+            // 文件系统剪枝：只保留 Device ID 匹配、且通过属主/权限过滤的条目
             children.retain(|entry_result| {
                 if let Ok(entry) = entry_result {
                     if let Ok(metadata) = entry.metadata() {
+                        // 目录不参与属主/权限过滤，否则会阻断对子目录的递归；
+                        // 符号链接也豁免，否则其自身的 lstat 权限位（通常 0777/root）
+                        // 会在 SYMLINK 检测前被过滤掉，破坏 symlink 报告。
+                        let kind = FileKind::from_metadata(&metadata);
                         metadata.dev() == target_dev
+                            && (metadata.is_dir()
+                                || kind == FileKind::Symlink
+                                || filter.matches(&metadata))
                     } else {
                         false
                     }
@@ -82,7 +349,7 @@ fn find_hard_links(search_path: &Path, target_inode: u64, target_dev: u64, skip_
         });
 
     // JWalk 迭代器在后台并行读取目录
-    let links: HashSet<PathBuf> = walker
+    let links: HashMap<PathBuf, FileKind> = walker
         .into_iter()
         .filter_map(|entry_result| {
             let entry = match entry_result {
@@ -93,23 +360,43 @@ fn find_hard_links(search_path: &Path, target_inode: u64, target_dev: u64, skip_
                     return None
                 },
             };
-            
+
             let path = entry.path();
-            
+
             let metadata = match entry.metadata() {
                 Ok(m) => m,
                 Err(_) => return None,
             };
 
+            let kind = FileKind::from_metadata(&metadata);
+
+            // 符号链接：解析其目标，若规范化后指向目标文件则作为 SYMLINK 结果报告
+            if kind == FileKind::Symlink {
+                if let (Some(target_canonical), Ok(resolved)) =
+                    (target_canonical.as_ref(), std::fs::canonicalize(&path))
+                {
+                    if &resolved == target_canonical {
+                        stream_record(&tx, &path, &metadata, kind, MatchKind::Symlink);
+                        return Some((path, kind));
+                    }
+                }
+                return None;
+            }
+
             // 核心检查：Inode 和 Device ID 都匹配，且非目录
             if !metadata.is_dir() && metadata.ino() == target_inode && metadata.dev() == target_dev {
-                Some(path)
+                // 目标文件本身也在 search_path 下且 Inode 自匹配，它稍后才会从结果集中移除；
+                // 流式输出时需在此显式排除，否则 ndjson 会把原始文件当作一条 hardlink 记录。
+                if path != target {
+                    stream_record(&tx, &path, &metadata, kind, MatchKind::Hardlink);
+                }
+                Some((path, kind))
             } else {
                 None
             }
         })
         .collect();
-    
+
     Ok(links)
 }
 
@@ -139,29 +426,84 @@ fn same_extents(extents1: &[FiemapExtent], extents2: &[FiemapExtent]) -> bool {
     })
 }
 
-/// 遍历搜索路径，查找与目标文件 Extent 列表完全相同的 Reflink 副本
+/// 将过滤后的 Extent 列表转换为按物理偏移排序的区间列表 `[fe_physical, fe_physical + fe_length)`。
+fn physical_intervals(extents: &[FiemapExtent]) -> Vec<(u64, u64)> {
+    let mut intervals: Vec<(u64, u64)> = extents
+        .iter()
+        .map(|e| (e.fe_physical, e.fe_physical + e.fe_length))
+        .collect();
+    intervals.sort_unstable();
+    intervals
+}
+
+/// 双指针归并两个已排序的物理区间列表，累加重叠的字节数。
+fn shared_bytes(intervals1: &[(u64, u64)], intervals2: &[(u64, u64)]) -> u64 {
+    let mut total = 0u64;
+    let (mut i, mut j) = (0usize, 0usize);
+    while i < intervals1.len() && j < intervals2.len() {
+        let (a_start, a_end) = intervals1[i];
+        let (b_start, b_end) = intervals2[j];
+
+        // 当前两个区间的重叠部分 [start, end)
+        let start = a_start.max(b_start);
+        let end = a_end.min(b_end);
+        if start < end {
+            total += end - start;
+        }
+
+        // 推进结束较早的一侧
+        if a_end < b_end {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    total
+}
+
+/// 遍历搜索路径，查找与目标文件共享物理数据块的 Reflink 副本。
+///
+/// 返回每个匹配文件到其共享块百分比的映射：完全共享为 `100.0`，部分共享时
+/// 按重叠的物理字节数 / 目标文件大小计算。只有共享比例不低于 `min_shared`
+/// 的文件才会被保留。`report_symlinks` 为真时，规范化后指向目标的符号链接
+/// 也会以 `0.0` 和 `FileKind::Symlink` 返回，不受 `min_shared` 限制。
 fn find_reflinked_files_by_extents(
-    search_path: &Path, 
-    target_extents: Vec<FiemapExtent>, 
-    target_dev: u64, 
-    target_inode: u64, 
-    target_size: u64, 
+    search_path: &Path,
+    target: &Path,
+    target_extents: Vec<FiemapExtent>,
+    target_dev: u64,
+    target_inode: u64,
+    target_size: u64,
+    min_shared: f64,
+    filter: MetaFilter,
+    tx: Option<Sender<Record>>,
+    report_symlinks: bool,
     skip_hidden: bool
-) -> io::Result<HashSet<PathBuf>> {
-    
+) -> io::Result<HashMap<PathBuf, (f64, FileKind)>> {
+
     let target_extent_count = target_extents.len();
-    
+    let target_intervals = physical_intervals(&target_extents);
+    // 预先规范化目标路径，用于判断符号链接是否指向它。
+    let target_canonical = std::fs::canonicalize(target).ok();
+
     let walker = WalkDir::new(search_path)
         .sort(false)
         .skip_hidden(skip_hidden) 
         .follow_links(false)
         .max_depth(std::usize::MAX)
         .process_read_dir(move |_depth, _path, _read_dir_state, children| {
-            // 文件系统剪枝：只保留 Device ID 匹配的条目
+            // 文件系统剪枝：只保留 Device ID 匹配、且通过属主/权限过滤的条目
             children.retain(|entry_result| {
                 if let Ok(entry) = entry_result {
                     if let Ok(metadata) = entry.metadata() {
+                        // 目录不参与属主/权限过滤，否则会阻断对子目录的递归；
+                        // 符号链接也豁免，否则其自身的 lstat 权限位（通常 0777/root）
+                        // 会在 SYMLINK 检测前被过滤掉，破坏 symlink 报告。
+                        let kind = FileKind::from_metadata(&metadata);
                         metadata.dev() == target_dev
+                            && (metadata.is_dir()
+                                || kind == FileKind::Symlink
+                                || filter.matches(&metadata))
                     } else {
                         false
                     }
@@ -171,7 +513,7 @@ fn find_reflinked_files_by_extents(
             });
         });
 
-    let reflinked_files: HashSet<PathBuf> = walker
+    let reflinked_files: HashMap<PathBuf, (f64, FileKind)> = walker
         .into_iter()
         .filter_map(|entry_result| {
             let entry = match entry_result {
@@ -190,22 +532,65 @@ fn find_reflinked_files_by_extents(
                 Err(_) => return None,
             };
             
+            let kind = FileKind::from_metadata(&metadata);
+
+            // 符号链接：解析其目标，若规范化后指向目标文件则作为 SYMLINK 结果报告。
+            // reflink 搜索可能独立于硬链接搜索运行（btrfs 目标 nlink==1 且无 -f），
+            // 因此这里也需报告指向目标的符号链接，否则它们会被完全漏掉；
+            // 但当硬链接搜索已运行（它也报告 symlink）时，这里必须跳过以免重复。
+            if kind == FileKind::Symlink {
+                if !report_symlinks {
+                    return None;
+                }
+                if let (Some(target_canonical), Ok(resolved)) =
+                    (target_canonical.as_ref(), std::fs::canonicalize(&path))
+                {
+                    if &resolved == target_canonical {
+                        stream_record(&tx, &path, &metadata, kind, MatchKind::Symlink);
+                        return Some((path, (0.0, kind)));
+                    }
+                }
+                return None;
+            }
+
             // 预剪枝：排除目录、目标文件本身、和大小不一致的文件
             if metadata.is_dir() || metadata.ino() == target_inode || metadata.len() != target_size {
                 return None;
             }
-            
+
+            // 非常规文件（设备节点/fifo/socket）不支持 fiemap，会返回
+            // ENXIO/ENOTTY 并污染告警输出，直接跳过。
+            if kind != FileKind::Regular {
+                return None;
+            }
+
             // 核心逻辑：获取当前文件的 Extent，并与目标 Extent 比较
             match get_extents(&path) {
                 Ok(extents) => {
-                    // 性能优化：快速失败机制 - Extent 数量不一致则跳过深度比较
-                    if extents.len() != target_extent_count {
+                    // 快速路径：Extent 列表完全一致，表示 100% 共享数据块 (Reflinked)
+                    if extents.len() == target_extent_count && same_extents(&extents, &target_extents) {
+                        stream_record(&tx, &path, &metadata, kind, MatchKind::Reflink { shared_percent: 100.0 });
+                        return Some((path, (100.0, kind)));
+                    }
+
+                    // 要求完全共享时，数量不一致即可跳过深度比较（保留原有快速失败优化）
+                    if min_shared >= 100.0 {
                         return None;
                     }
-                                            
-                    if same_extents(&extents, &target_extents) {
-                        // Extent 列表完全一致，表示完全共享数据块 (Reflinked)
-                        Some(path)
+
+                    // 部分共享：对两个排序后的物理区间列表做双指针归并，累加重叠字节数，
+                    // 再除以目标大小得到共享百分比。
+                    let intervals = physical_intervals(&extents);
+                    let shared = shared_bytes(&target_intervals, &intervals);
+                    let pct = if target_size > 0 {
+                        shared as f64 / target_size as f64 * 100.0
+                    } else {
+                        0.0
+                    };
+
+                    if pct >= min_shared {
+                        stream_record(&tx, &path, &metadata, kind, MatchKind::Reflink { shared_percent: pct });
+                        Some((path, (pct, kind)))
                     } else {
                         None
                     }
@@ -228,6 +613,265 @@ fn find_reflinked_files_by_extents(
 }
 
 
+// ============== 去重动作：FICLONE / FIDEDUPERANGE ==============
+
+// 来自 <linux/fs.h> 的去重结构体定义（单目标，dest_count 固定为 1）。
+#[repr(C)]
+struct FileDedupeRangeInfo {
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+}
+
+#[repr(C)]
+struct FileDedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+    info: [FileDedupeRangeInfo; 1],
+}
+
+// FICLONE = _IOW(0x94, 9, int)：用源文件的 reflink 整体替换目标文件内容。
+nix::ioctl_write_int!(ficlone, 0x94, 9);
+// FIDEDUPERANGE = _IOWR(0x94, 54, struct file_dedupe_range)：按范围去重。
+// 内核头部把请求大小按 `struct file_dedupe_range` 的柔性数组（info[0]）计为 24 字节，
+// 而我们的结构体内联了 `info: [_; 1]` 使 size_of = 56，会生成错误的 cmd（0xC0389436）
+// 并在真实 Btrfs 上以 ENOTTY 失败。因此用 ioctl_readwrite_bad! 显式指定 24 字节，
+// 生成与内核一致的 cmd（0xC0189436）。
+nix::ioctl_readwrite_bad!(fideduperange, nix::request_code_readwrite!(0x94, 54, 24), FileDedupeRange);
+
+/// 逐字节比较两个文件的内容是否完全一致（破坏性去重前的安全校验）。
+fn contents_identical(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut reader_a = io::BufReader::new(File::open(a)?);
+    let mut reader_b = io::BufReader::new(File::open(b)?);
+
+    let mut buf_a = [0u8; 64 * 1024];
+    let mut buf_b = [0u8; 64 * 1024];
+
+    loop {
+        let n_a = read_full(&mut reader_a, &mut buf_a)?;
+        let n_b = read_full(&mut reader_b, &mut buf_b)?;
+        if n_a != n_b {
+            return Ok(false);
+        }
+        if n_a == 0 {
+            return Ok(true);
+        }
+        if buf_a[..n_a] != buf_b[..n_b] {
+            return Ok(false);
+        }
+    }
+}
+
+/// 尽量填满缓冲区，返回实际读取的字节数（0 表示 EOF）。
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// 对 `src`（目标文件）与 `dest` 做内核级去重 (FIDEDUPERANGE)，返回实际去重的字节数。
+///
+/// 内核对单次去重请求有长度上限（历史上为 16 MiB），大文件一次调用只会返回部分
+/// `bytes_deduped`。因此这里按偏移循环提交，直到覆盖整个范围，确保大文件被完整去重。
+fn dedupe_file(src: &File, dest: &File, length: u64) -> io::Result<u64> {
+    let mut total_deduped = 0u64;
+    let mut offset = 0u64;
+
+    while offset < length {
+        let mut range = FileDedupeRange {
+            src_offset: offset,
+            src_length: length - offset,
+            dest_count: 1,
+            reserved1: 0,
+            reserved2: 0,
+            info: [FileDedupeRangeInfo {
+                dest_fd: dest.as_raw_fd() as i64,
+                dest_offset: offset,
+                bytes_deduped: 0,
+                status: 0,
+                reserved: 0,
+            }],
+        };
+
+        // 注意：FIDEDUPERANGE 在源 fd 上发起，目标通过 info.dest_fd 给出。
+        unsafe {
+            fideduperange(src.as_raw_fd(), &mut range)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("FIDEDUPERANGE 失败: {}", e)))?;
+        }
+
+        let info = &range.info[0];
+        // FILE_DEDUPE_RANGE_DIFFERS = 1：内核复查后认为内容不一致。
+        if info.status == 1 {
+            return Err(io::Error::new(io::ErrorKind::Other, "内核复查内容不一致，未去重"));
+        }
+        if info.status < 0 {
+            return Err(io::Error::from_raw_os_error(-info.status));
+        }
+
+        // 内核未推进（返回 0 字节）时终止循环，避免死循环。
+        if info.bytes_deduped == 0 {
+            break;
+        }
+
+        total_deduped += info.bytes_deduped;
+        offset += info.bytes_deduped;
+    }
+
+    Ok(total_deduped)
+}
+
+/// 用指向 `src` 的全新 reflink 替换独立副本 `dest` (FICLONE)。
+fn clone_file(src: &File, dest: &File) -> io::Result<()> {
+    // FICLONE 在目标 fd 上发起，参数为源 fd。
+    unsafe {
+        ficlone(dest.as_raw_fd(), src.as_raw_fd() as u64)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("FICLONE 失败: {}", e)))?;
+    }
+    Ok(())
+}
+
+/// 遍历搜索路径，查找与目标文件大小相同、内容逐字节一致、但尚未完全共享物理块的
+/// 常规文件，作为去重/clone 的候选集。
+fn find_dedupe_candidates(
+    search_path: &Path,
+    target: &Path,
+    target_extents: &[FiemapExtent],
+    target_dev: u64,
+    target_inode: u64,
+    target_size: u64,
+    skip_hidden: bool,
+) -> io::Result<Vec<PathBuf>> {
+
+    let target_extent_count = target_extents.len();
+
+    let walker = WalkDir::new(search_path)
+        .sort(true)
+        .skip_hidden(skip_hidden)
+        .follow_links(false)
+        .max_depth(std::usize::MAX)
+        .process_read_dir(move |_depth, _path, _read_dir_state, children| {
+            children.retain(|entry_result| {
+                if let Ok(entry) = entry_result {
+                    if let Ok(metadata) = entry.metadata() {
+                        return metadata.dev() == target_dev;
+                    }
+                }
+                false
+            });
+        });
+
+    let mut candidates = Vec::new();
+    for entry_result in walker {
+        let entry = match entry_result {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("[JWalk 警告] 遍历错误：{}", e);
+                continue;
+            }
+        };
+
+        let path = entry.path().to_path_buf();
+        let metadata = match entry.metadata() {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+
+        // 只处理大小一致的常规文件，且排除目标本身及其硬链接。
+        if FileKind::from_metadata(&metadata) != FileKind::Regular {
+            continue;
+        }
+        if metadata.ino() == target_inode || metadata.len() != target_size {
+            continue;
+        }
+
+        // 已完全共享物理块的文件无需再去重。
+        if let Ok(extents) = get_extents(&path) {
+            if extents.len() == target_extent_count && same_extents(&extents, target_extents) {
+                continue;
+            }
+        }
+
+        // 破坏性操作前的安全校验：要求内容逐字节一致。
+        match contents_identical(target, &path) {
+            Ok(true) => candidates.push(path),
+            Ok(false) => {}
+            Err(e) => eprintln!("[警告] 无法比较文件 {} 的内容: {}", path.display(), e),
+        }
+    }
+
+    Ok(candidates)
+}
+
+/// 对候选文件执行去重 (FIDEDUPERANGE) 或 clone (FICLONE)，打印逐文件结果，返回节省的总字节数。
+fn perform_dedupe(
+    candidates: &[PathBuf],
+    target: &Path,
+    target_size: u64,
+    use_clone: bool,
+    dry_run: bool,
+    machine: bool,
+) -> io::Result<u64> {
+
+    let action = if use_clone { "CLONE" } else { "DEDUPE" };
+    let mut total_saved = 0u64;
+
+    let src = File::open(target)?;
+
+    for path in candidates {
+        if dry_run {
+            // 机器可读格式下逐文件进度走 stderr，避免污染 stdout 上的 JSON/NDJSON。
+            if machine {
+                eprintln!("[DRY-RUN {}] {} (可回收约 {} bytes)", action, path.display(), target_size);
+            } else {
+                println!("[DRY-RUN {}] {} (可回收约 {} bytes)", action, path.display(), target_size);
+            }
+            total_saved += target_size;
+            continue;
+        }
+
+        // 两种模式都需要对副本的写权限（dedupe 需要 O_RDWR，clone 会覆盖内容）。
+        let dest = match std::fs::OpenOptions::new().read(true).write(true).open(path) {
+            Ok(f) => f,
+            Err(e) => {
+                eprintln!("[警告] 无法以写方式打开 {}: {}", path.display(), e);
+                continue;
+            }
+        };
+
+        let result = if use_clone {
+            clone_file(&src, &dest).map(|_| target_size)
+        } else {
+            dedupe_file(&src, &dest, target_size)
+        };
+
+        match result {
+            Ok(saved) => {
+                total_saved += saved;
+                if machine {
+                    eprintln!("[{}] {} (节省 {} bytes)", action, path.display(), saved);
+                } else {
+                    println!("[{}] {} (节省 {} bytes)", action, path.display(), saved);
+                }
+            }
+            Err(e) => eprintln!("[警告] {} 处理 {} 失败: {}", action, path.display(), e),
+        }
+    }
+
+    Ok(total_saved)
+}
+
 // ============== 主函数 ==============
 
 fn main() -> io::Result<()> {
@@ -236,15 +880,48 @@ fn main() -> io::Result<()> {
     let start_time = Instant::now();
     
     let mut total_results: HashSet<PathBuf> = HashSet::new();
-    
+    // 记录每个 Reflink 匹配的共享块百分比，用于详细列表中的 `[REFLINK 87%]` 输出。
+    let mut reflink_pcts: HashMap<PathBuf, f64> = HashMap::new();
+    // 记录每个结果的文件类型，用于详细列表中的类型列输出。
+    let mut file_kinds: HashMap<PathBuf, FileKind> = HashMap::new();
+
+    let filter = MetaFilter::from_args(&args);
+    // 机器可读格式下抑制人类摘要，只输出结构化记录。
+    let machine = args.format != OutputFormat::Human;
+
+    // ndjson：启动后台写出线程，匹配一经发现即通过通道流式输出，避免缓冲整个结果集。
+    let (record_tx, writer_handle) = if args.format == OutputFormat::Ndjson {
+        let (tx, rx) = std::sync::mpsc::channel::<Record>();
+        let handle = std::thread::spawn(move || {
+            let stdout = io::stdout();
+            let mut lock = stdout.lock();
+            for rec in rx {
+                let _ = writeln!(lock, "{}", rec.to_json());
+            }
+        });
+        (Some(tx), Some(handle))
+    } else {
+        (None, None)
+    };
+
     // --- 1. 获取目标文件信息 ---
-    let (target_inode, target_dev, target_nlink, target_size) = match get_target_inode_info(&args.target) {
-        Ok((i, d, n, s)) => (i, d, n, s),
+    let target_info = match get_target_inode_info(&args.target) {
+        Ok(info) => info,
         Err(e) => {
             eprintln!("[错误] 无法获取目标文件 {} 的信息：{}", args.target.display(), e);
             return Err(e);
         }
     };
+    let TargetInfo {
+        inode: target_inode,
+        dev: target_dev,
+        nlink: target_nlink,
+        size: target_size,
+        mode: target_mode,
+        uid: target_uid,
+        gid: target_gid,
+        mtime: target_mtime,
+    } = target_info;
     
     // 检查 Btrfs 状态
     let is_btrfs = match is_on_btrfs(&args.target) {
@@ -258,27 +935,42 @@ fn main() -> io::Result<()> {
     // 确定 Reflink 搜索是否执行
     let perform_reflink_search = is_btrfs && !args.disable_reflink;
     
-    println!("目标文件: {} [Inode: {}, Dev: {}, Size: {} bytes]", 
-        args.target.display(), target_inode, target_dev, target_size);
+    if !machine {
+        println!("目标文件: {} [Inode: {}, Dev: {}, Size: {} bytes, Mode: {:04o}, UID: {}, GID: {}, Mtime: {}]",
+            args.target.display(), target_inode, target_dev, target_size,
+            target_mode, target_uid, target_gid, target_mtime);
+
+        if filter.is_active() {
+            println!("  -> 过滤条件: {}{}{}",
+                filter.uid.map(|u| format!("uid={} ", u)).unwrap_or_default(),
+                filter.gid.map(|g| format!("gid={} ", g)).unwrap_or_default(),
+                filter.mode.map(|m| format!("mode={:04o} ", m)).unwrap_or_default());
+        }
+    }
 
     
     // --- 2. Inode 硬链接查找 ---
     let perform_hardlink_search = target_nlink > 1 || args.force_hardlink;
 
     if perform_hardlink_search {
-        print!("  -> 硬链接查找: 搜索 {}... ", args.search_path.display());
-        io::stdout().flush().unwrap();
-        
-        let mut hard_links_set = find_hard_links(&args.search_path, target_inode, target_dev, args.skip_hidden)?;
-        
+        if !machine {
+            print!("  -> 硬链接查找: 搜索 {}... ", args.search_path.display());
+            io::stdout().flush().unwrap();
+        }
+
+        let mut hard_links_set = find_hard_links(&args.search_path, &args.target, target_inode, target_dev, filter, record_tx.clone(), args.skip_hidden)?;
+
         // 优化: 移除自身路径，避免昂贵的 canonicalize
-        hard_links_set.remove(&args.target); 
-        total_results.extend(hard_links_set);
-        
+        hard_links_set.remove(&args.target);
+        for (path, kind) in hard_links_set {
+            total_results.insert(path.clone());
+            file_kinds.insert(path, kind);
+        }
+
         if target_nlink <= 1 {
             eprintln!("\n[提示] 目标文件 nlink=1，因 -f 强制执行查找。");
         }
-    } else {
+    } else if !machine {
         println!("  -> 硬链接查找: nlink=1，跳过。使用 -f 强制执行。");
     }
 
@@ -287,9 +979,11 @@ fn main() -> io::Result<()> {
     let mut btrfs_shared_count = 0;
     if perform_reflink_search {
         
-        print!("  -> Reflink 查找: 目标在 Btrfs 上。提取 Extent... ");
-        io::stdout().flush().unwrap();
-        
+        if !machine {
+            print!("  -> Reflink 查找: 目标在 Btrfs 上。提取 Extent... ");
+            io::stdout().flush().unwrap();
+        }
+
         let target_extents = match get_extents(&args.target) {
             Ok(extents) => extents,
             Err(e) => {
@@ -300,23 +994,35 @@ fn main() -> io::Result<()> {
         
         // 优化点 1: Inline Data 警告处理
         if target_extents.is_empty() && target_size > 0 {
-            println!("跳过。");
+            if !machine {
+                println!("跳过。");
+            }
             eprintln!("[警告] 目标文件 ({}) 是 Inline Data。Reflink 检查不可靠，已跳过。", args.target.display());
         } else {
-            print!("完成 (Extents: {})。开始并行比较... ", target_extents.len());
-            io::stdout().flush().unwrap();
+            if !machine {
+                print!("完成 (Extents: {})。开始并行比较... ", target_extents.len());
+                io::stdout().flush().unwrap();
+            }
 
-            match find_reflinked_files_by_extents(&args.search_path, target_extents, target_dev, target_inode, target_size, args.skip_hidden) {
+            // 硬链接搜索已运行时它会报告指向目标的符号链接；此处避免重复报告。
+            match find_reflinked_files_by_extents(&args.search_path, &args.target, target_extents, target_dev, target_inode, target_size, args.min_shared, filter, record_tx.clone(), !perform_hardlink_search, args.skip_hidden) {
                 Ok(btrfs_results) => {
-                    btrfs_shared_count = btrfs_results.len(); 
-                    total_results.extend(btrfs_results);
+                    // 仅统计真正共享数据块的副本；指向目标的符号链接不计入 Reflink 数。
+                    btrfs_shared_count = btrfs_results.values()
+                        .filter(|(_, kind)| *kind != FileKind::Symlink)
+                        .count();
+                    for (path, (pct, kind)) in btrfs_results {
+                        total_results.insert(path.clone());
+                        reflink_pcts.insert(path.clone(), pct);
+                        file_kinds.insert(path, kind);
+                    }
                 }
                 Err(e) => {
                     eprintln!("\n[Reflink 查找失败] {}", e);
                 }
             }
         }
-    } else {
+    } else if !machine {
         if is_btrfs {
              println!("  -> Reflink 查找: 目标在 Btrfs 上，已通过 -r 显式禁用。");
         } else {
@@ -327,49 +1033,169 @@ fn main() -> io::Result<()> {
     // --- 4. 输出结果 ---
     let final_count = total_results.len();
     let elapsed = start_time.elapsed();
-    
-    // 硬链接计数来自 total_results 中 Inode 匹配的项
-    let hard_links_found = total_results.iter()
-        .filter_map(|p| p.metadata().ok())
-        .filter(|m| m.ino() == target_inode)
-        .count();
-    
-    println!("\n--- 查找结果摘要 (耗时: {:.2?}) ---", elapsed);
-    // Reflink 计数来自专用搜索结果，它已经排除了硬链接
-    println!("总共找到 {} 个副本 (硬链接: {}, Reflink: {})", 
-        final_count, 
-        hard_links_found, 
-        btrfs_shared_count);
-    
-    if final_count > 0 {
-        println!("--- 详细列表 ---");
-        // 将结果转换为 Vec 并排序，以便输出顺序稳定
-        let mut sorted_results: Vec<PathBuf> = total_results.into_iter().collect();
-        sorted_results.sort();
-        
-        for link in sorted_results.iter() {
-            let metadata = match link.metadata() {
-                Ok(m) => m,
-                Err(_) => {
-                    println!("[已消失] {}", link.display());
-                    continue;
+
+    // 将结果转换为 Vec 并排序，以便输出顺序稳定
+    let mut sorted_results: Vec<PathBuf> = total_results.into_iter().collect();
+    sorted_results.sort();
+
+    // 将一条已排序的结果路径解析为结构化记录；路径消失时返回 None。
+    // 使用 lstat（symlink_metadata）而非 metadata，与流式 NDJSON 记录一致：
+    // 对符号链接报告其自身的 inode/size，而不是跟随解析到的目标。
+    let to_record = |link: &Path| -> Option<Record> {
+        let metadata = std::fs::symlink_metadata(link).ok()?;
+        let kind = file_kinds.get(link).copied().unwrap_or(FileKind::Unknown);
+        let match_kind = if kind == FileKind::Symlink {
+            MatchKind::Symlink
+        } else if metadata.ino() == target_inode {
+            MatchKind::Hardlink
+        } else if let Some(pct) = reflink_pcts.get(link) {
+            MatchKind::Reflink { shared_percent: *pct }
+        } else {
+            // 未被 Reflink 搜索标注，保守地按 100% 处理。
+            MatchKind::Reflink { shared_percent: 100.0 }
+        };
+        Some(Record {
+            path: link.to_path_buf(),
+            inode: metadata.ino(),
+            dev: metadata.dev(),
+            size: metadata.len(),
+            kind,
+            match_kind,
+        })
+    };
+
+    match args.format {
+        OutputFormat::Ndjson => {
+            // 记录已在搜索过程中通过通道流式写出，这里无需再做任何事。
+        }
+        OutputFormat::Json => {
+            // 排序后一次性序列化整个结果集为 JSON 数组文档。
+            let records: Vec<String> = sorted_results.iter()
+                .filter_map(|link| to_record(link))
+                .map(|r| r.to_json())
+                .collect();
+            let stdout = io::stdout();
+            let mut lock = stdout.lock();
+            writeln!(lock, "[{}]", records.join(","))?;
+        }
+        OutputFormat::Human => {
+            // 硬链接计数来自结果集中 Inode 匹配的项（指向目标的符号链接不计入）
+            let hard_links_found = sorted_results.iter()
+                .filter(|p| file_kinds.get(*p) != Some(&FileKind::Symlink))
+                .filter_map(|p| p.metadata().ok())
+                .filter(|m| m.ino() == target_inode)
+                .count();
+
+            println!("\n--- 查找结果摘要 (耗时: {:.2?}) ---", elapsed);
+            // Reflink 计数来自专用搜索结果，它已经排除了硬链接
+            println!("总共找到 {} 个副本 (硬链接: {}, Reflink: {})",
+                final_count,
+                hard_links_found,
+                btrfs_shared_count);
+
+            if final_count > 0 {
+                println!("--- 详细列表 ---");
+                for link in sorted_results.iter() {
+                    let metadata = match link.metadata() {
+                        Ok(m) => m,
+                        Err(_) => {
+                            println!("[已消失] {}", link.display());
+                            continue;
+                        }
+                    };
+
+                    let kind = file_kinds.get(link).copied().unwrap_or(FileKind::Unknown);
+
+                    // 确定状态：SYMLINK (指向目标的符号链接) / HARDLINK (Inode 匹配) /
+                    // REFLINK (非 Inode 匹配，但被 Reflink 搜索找到)
+                    let status = if kind == FileKind::Symlink {
+                        "SYMLINK".to_string()
+                    } else if metadata.ino() == target_inode {
+                        "HARDLINK".to_string()
+                    } else if let Some(pct) = reflink_pcts.get(link) {
+                        // Reflink 结果集中的一个，附带共享块百分比
+                        format!("REFLINK {}%", pct.round() as u64)
+                    } else if perform_reflink_search {
+                        "REFLINK".to_string()
+                    } else {
+                        "?".to_string()
+                    };
+
+                    println!(
+                        "[{:<11}] [{:<7}] mode={:04o} uid={} gid={} mtime={} {}",
+                        status,
+                        kind.label(),
+                        metadata.mode() & 0o7777,
+                        metadata.uid(),
+                        metadata.gid(),
+                        metadata.mtime(),
+                        link.display()
+                    );
                 }
-            };
+            } else {
+                println!("\n[提示] 未找到其他链接或共享文件。");
+            }
+        }
+    }
+
+    // 关闭通道（丢弃发送端）并等待 ndjson 写出线程排空剩余记录。
+    drop(record_tx);
+    if let Some(handle) = writer_handle {
+        let _ = handle.join();
+    }
 
-            // 确定状态：HARDLINK (Inode 匹配) 或 REFLINK (非 Inode 匹配，但被 Reflink 搜索找到)
-            let status = if metadata.ino() == target_inode {
-                "HARDLINK"
-            } else if perform_reflink_search {
-                // 如果启用了 Reflink 搜索，且此文件不是硬链接，则它是 Reflink 结果集中的一个
-                "REFLINK"
+    // --- 5. 去重 / Clone 动作 ---
+    // 在只读搜索完成后处理候选集，把与目标内容一致但未共享物理块的独立副本
+    // 折叠为 Btrfs reflink，从而回收空间。
+    if args.dedupe || args.clone {
+        if !is_btrfs {
+            eprintln!("[错误] --dedupe/--clone 仅支持 Btrfs 文件系统，已跳过。");
+        } else {
+            let use_clone = args.clone;
+            let action = if use_clone { "Clone" } else { "去重" };
+            // 机器可读格式下，动作的人类摘要走 stderr，保持 stdout 仅含 JSON/NDJSON。
+            if machine {
+                eprintln!("\n--- {}动作{} ---", action, if args.dry_run { " (dry-run)" } else { "" });
             } else {
-                "?" 
-            };
-            
-            println!("[{}] {}", status, link.display());
+                println!("\n--- {}动作{} ---", action, if args.dry_run { " (dry-run)" } else { "" });
+            }
+
+            // 去重/clone 需要目标的物理 Extent 以排除已共享的副本。
+            let target_extents = get_extents(&args.target).unwrap_or_default();
+
+            let candidates = find_dedupe_candidates(
+                &args.search_path,
+                &args.target,
+                &target_extents,
+                target_dev,
+                target_inode,
+                target_size,
+                args.skip_hidden,
+            )?;
+
+            if candidates.is_empty() {
+                if machine {
+                    eprintln!("[提示] 未找到需要{}的独立副本。", action);
+                } else {
+                    println!("[提示] 未找到需要{}的独立副本。", action);
+                }
+            } else {
+                let total_saved = perform_dedupe(
+                    &candidates,
+                    &args.target,
+                    target_size,
+                    use_clone,
+                    args.dry_run,
+                    machine,
+                )?;
+                let verb = if args.dry_run { "可回收" } else { "已回收" };
+                if machine {
+                    eprintln!("{} {} 个文件，{} {} bytes。", action, candidates.len(), verb, total_saved);
+                } else {
+                    println!("{} {} 个文件，{} {} bytes。", action, candidates.len(), verb, total_saved);
+                }
+            }
         }
-    } else {
-        println!("\n[提示] 未找到其他链接或共享文件。");
     }
 
     Ok(())